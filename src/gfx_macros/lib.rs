@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#![feature(macro_rules, plugin_registrar, quote)]
+#![feature(plugin_registrar, quote)]
 
 //! Macro extensions crate.
 //! Implements `shaders!` macro as well as `#[shader_param]` and
@@ -21,15 +21,32 @@
 extern crate rustc;
 extern crate syntax;
 
+use std::collections::HashSet;
 use syntax::{ast, attr, ext, codemap};
 use syntax::ext::build::AstBuilder;
-use syntax::parse::token;
 use syntax::fold::Folder;
+use syntax::parse::token;
 use syntax::ptr::P;
 
+// `shader_param.rs` and `vertex_format.rs` are not checked into this
+// branch. `extern_crate_hack`/`ExternCrateHackFolder` below are kept
+// (not deleted) so those files, wherever they live, keep working
+// unmodified against them.
 pub mod shader_param;
 pub mod vertex_format;
 
+/// The name to link the `gfx` crate under. Defaults to `"gfx"`, but can be
+/// overridden at `gfx_macros`' own compile time via the `GFX_CRATE_NAME`
+/// environment variable, for a downstream build that vendors or publishes
+/// the dependency under a different package name. A crate's SVH (and its
+/// own `CrateMetadata.name`) aren't knowable to its own build in time to
+/// bake them in, so an explicit override - rather than trying to infer
+/// the name from the compilation session - is the only thing that's
+/// actually derivable here.
+fn gfx_crate_name() -> token::InternedString {
+    token::intern_and_get_ident(option_env!("GFX_CRATE_NAME").unwrap_or("gfx"))
+}
+
 /// Entry point for the plugin phase
 #[plugin_registrar]
 pub fn registrar(reg: &mut rustc::plugin::Registry) {
@@ -41,6 +58,8 @@ pub fn registrar(reg: &mut rustc::plugin::Registry) {
     // Register the `#[vertex_format]` attribute.
     reg.register_syntax_extension(intern("vertex_format"),
         base::Decorator(box vertex_format::expand));
+    // Register the `shaders!` macro.
+    reg.register_macro("shaders", expand_shaders);
 }
 
 /// Scan through the field's attributes and extract the field vertex name. If
@@ -72,14 +91,20 @@ fn find_name(cx: &mut ext::base::ExtCtxt, span: codemap::Span,
 /// Marker string to base the unique identifier generated by `extern_crate_hack()` on
 static EXTERN_CRATE_HACK: &'static str = "__gfx_extern_crate_hack";
 
-/// Inserts a module with a unique identifier that reexports
-/// The `gfx` crate, and returns that identifier
+/// Inserts a module with a unique identifier that reexports the `gfx`
+/// crate (linked under the name `gfx_crate_name` returns: `"gfx"` unless
+/// `GFX_CRATE_NAME` was set when `gfx_macros` was compiled; the name is not
+/// looked up in the compilation session), and returns that identifier. This is
+/// what makes generated references resolve correctly no matter what local
+/// alias the invoking crate bound its own `extern crate gfx` to: the
+/// splice below re-links the crate itself rather than assuming any
+/// particular name is already bound at the call site.
 fn extern_crate_hack(context: &mut ext::base::ExtCtxt,
                      span: codemap::Span,
                      push: |P<ast::Item>|) -> ast::Ident {
     let extern_crate_hack = token::gensym_ident(EXTERN_CRATE_HACK);
     // mod $EXTERN_CRATE_HACK {
-    //     extern crate gfx_ = "gfx";
+    //     extern crate gfx_ = "<gfx_crate_name()>";
     //     pub use gfx_ as gfx;
     // }
     let item = context.item_mod(
@@ -95,7 +120,7 @@ fn extern_crate_hack(context: &mut ext::base::ExtCtxt,
                 node: ast::ViewItemExternCrate(
                     context.ident_of("gfx_"),
                     Some((
-                        token::InternedString::new("gfx"),
+                        gfx_crate_name(),
                         ast::CookedStr
                     )),
                     ast::DUMMY_NODE_ID
@@ -161,84 +186,85 @@ fn fixup_extern_crate_paths(item: P<ast::Item>, path_root: ast::Ident) -> P<ast:
     }.fold_item(item).into_iter().next().unwrap()
 }
 
-// The `gfx` reexport module here does not need a unique name,
-// as it gets inserted in a new block and thus doesn't conflict with
-// any names outside its lexical scope.
-#[macro_export]
-macro_rules! shaders {
-    (GLSL_120: $v:expr $($t:tt)*) => {
-        {
-            mod __gfx_extern_crate_hack {
-                extern crate "gfx" as gfx_;
-                pub use self::gfx_ as gfx;
-            }
-            __gfx_extern_crate_hack::gfx::ShaderSource {
-                glsl_120: Some($v),
-                ..shaders!($($t)*)
-            }
-        }
-    };
-    (GLSL_130: $v:expr $($t:tt)*) => {
-        {
-            mod __gfx_extern_crate_hack {
-                extern crate "gfx" as gfx_;
-                pub use self::gfx_ as gfx;
-            }
-            __gfx_extern_crate_hack::gfx::ShaderSource {
-                glsl_130: Some($v),
-                ..shaders!($($t)*)
-            }
-        }
-    };
-    (GLSL_140: $v:expr $($t:tt)*) => {
-        {
-            mod __gfx_extern_crate_hack {
-                extern crate "gfx" as gfx_;
-                pub use self::gfx_ as gfx;
-            }
-            __gfx_extern_crate_hack::gfx::ShaderSource {
-                glsl_140: Some($v),
-                ..shaders!($($t)*)
-            }
-        }
-    };
-    (GLSL_150: $v:expr $($t:tt)*) => {
-        {
-            mod __gfx_extern_crate_hack {
-                extern crate "gfx" as gfx_;
-                pub use self::gfx_ as gfx;
-            }
-            __gfx_extern_crate_hack::gfx::ShaderSource {
-                glsl_150: Some($v),
-                ..shaders!($($t)*)
-            }
-        }
-    };
-    (TARGETS: $v:expr $($t:tt)*) => {
-        {
-            mod __gfx_extern_crate_hack {
-                extern crate "gfx" as gfx_;
-                pub use self::gfx_ as gfx;
+/// The keys `shaders!` accepts, paired with the `ShaderSource` field each
+/// fills in. Kept as a static table (rather than lowercasing the key at
+/// expansion time) so adding a backend is a one-line addition here.
+static SHADER_KEYS: &'static [(&'static str, &'static str)] = &[
+    ("GLSL_120", "glsl_120"),
+    ("GLSL_130", "glsl_130"),
+    ("GLSL_140", "glsl_140"),
+    ("GLSL_150", "glsl_150"),
+    ("TARGETS", "targets"),
+];
+
+/// Expands `shaders! { KEY: expr, KEY: expr, ... }` into a `ShaderSource`
+/// literal. Parses its own `KEY: expr` token sequence (rather than being a
+/// `macro_rules!` arm per key) so it can reject an unknown key or a
+/// version supplied twice with a proper `span_err` instead of silently
+/// overwriting the earlier value.
+///
+/// Splices a single private `extern_crate_hack` reexport (the same
+/// mechanism item-level decorators use) so the `ShaderSource` path
+/// resolves no matter what local alias the invoking crate bound its own
+/// `extern crate gfx` to, and builds the struct path directly off the
+/// returned identifier - no `Folder` pass is needed here since there is
+/// only the one path to get right.
+fn expand_shaders(cx: &mut ext::base::ExtCtxt, sp: codemap::Span, tts: &[ast::TokenTree])
+                   -> Box<ext::base::MacResult+'static> {
+    let mut parser = cx.new_parser_from_tts(tts);
+    let mut values: Vec<Option<syntax::ptr::P<ast::Expr>>> = SHADER_KEYS.iter().map(|_| None).collect();
+    let mut seen = HashSet::new();
+
+    while parser.token != token::Eof {
+        let key_span = parser.span;
+        let key = parser.parse_ident();
+        parser.expect(&token::Colon);
+        let value = parser.parse_expr();
+
+        let key_str = key.as_str();
+        match SHADER_KEYS.iter().position(|&(k, _)| k == key_str) {
+            Some(index) => {
+                if !seen.insert(index) {
+                    cx.span_err(key_span, format!(
+                        "duplicate shader key `{}`", key_str).as_slice());
+                } else {
+                    values[index] = Some(value);
+                }
             }
-            __gfx_extern_crate_hack::gfx::ShaderSource {
-                targets: Some($v),
-                ..shaders!($($t)*)
+            None => {
+                let mut known = String::new();
+                for (i, &(k, _)) in SHADER_KEYS.iter().enumerate() {
+                    if i > 0 { known.push_str(", "); }
+                    known.push_str(k);
+                }
+                cx.span_err(key_span, format!(
+                    "unknown shader key `{}`, expected one of {}",
+                    key_str, known).as_slice());
             }
         }
-    };
-    () => {
-        {
-            mod __gfx_extern_crate_hack {
-                extern crate "gfx" as gfx_;
-                pub use self::gfx_ as gfx;
-            }
-            __gfx_extern_crate_hack::gfx::ShaderSource {
-                glsl_120: None,
-                glsl_130: None,
-                glsl_140: None,
-                glsl_150: None,
-                targets: None,
-            }
+
+        if parser.token == token::Comma {
+            parser.bump();
         }
     }
+
+    let mut extern_crate_item = None;
+    let root = extern_crate_hack(cx, sp, |item| extern_crate_item = Some(item));
+
+    let struct_path = cx.path(sp, vec![root, cx.ident_of("gfx"), cx.ident_of("ShaderSource")]);
+    let struct_fields = SHADER_KEYS.iter().zip(values.into_iter()).map(|(&(_, field), value)| {
+        let expr = match value {
+            Some(expr) => cx.expr_some(sp, expr),
+            None => cx.expr_none(sp),
+        };
+        cx.field_imm(sp, cx.ident_of(field), expr)
+    }).collect();
+    let struct_expr = cx.expr_struct(sp, struct_path, struct_fields);
+
+    let block = cx.block(
+        sp,
+        vec![cx.stmt_item(sp, extern_crate_item.unwrap())],
+        Some(struct_expr)
+    );
+    ext::base::MacExpr::new(cx.expr_block(block))
 }